@@ -1,20 +1,31 @@
 //! A mostly reverse-engineered implementation of LNURLPay following <https://bolt.fun/guide/web-services/lnurl/pay>
 
-use axum::extract::{Query, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::routing::get;
 use axum::{Json, Router};
 use cln_plugin::options::{ConfigOption, Value};
-use cln_rpc::model::InvoiceRequest;
+use cln_rpc::model::{
+    InvoiceRequest, ListinvoicesInvoicesStatus, ListinvoicesRequest, WaitinvoiceRequest,
+    WaitinvoiceStatus,
+};
 use cln_rpc::primitives::{Amount, AmountOrAny};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::io::{stdin, stdout};
+use tokio::sync::Mutex;
 use url::Url;
 use uuid::Uuid;
 
-use nostr::event::Event;
+use nostr::event::{Event, Tag, TagKind};
+use nostr::key::FromSkStr;
+use nostr::{EventBuilder, Keys, Kind};
+use nostr_sdk::Client;
+
+use bech32::{ToBase32, Variant};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -41,6 +52,37 @@ async fn main() -> anyhow::Result<()> {
             Value::OptString,
             "Nostr pub key of zapper",
         ))
+        .option(ConfigOption::new(
+            "clnurl_success_message",
+            Value::OptString,
+            "Message to show the wallet after a successful payment (LUD-09 successAction)",
+        ))
+        .option(ConfigOption::new(
+            "clnurl_success_url",
+            Value::OptString,
+            "URL to show the wallet after a successful payment (LUD-09 successAction)",
+        ))
+        .option(ConfigOption::new(
+            "clnurl_nostr_privkey",
+            Value::OptString,
+            "Nostr private key (hex or nsec) used to sign NIP-57 zap receipts",
+        ))
+        .option(ConfigOption::new(
+            "clnurl_relays",
+            Value::OptString,
+            "Comma separated list of relays zap receipts are published to",
+        ))
+        .option(ConfigOption::new(
+            "clnurl_users_file",
+            Value::OptString,
+            "Path to a JSON file mapping username to {description,minSendable,maxSendable}, \
+            served as Lightning Addresses under /.well-known/lnurlp/<username>",
+        ))
+        .option(ConfigOption::new(
+            "clnurl_comment_allowed",
+            Value::Integer(0),
+            "Maximum length in bytes of a LUD-12 comment accepted on /invoice, 0 to disable",
+        ))
         .dynamic()
         .start(())
         .await?
@@ -81,16 +123,83 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    let success_message = match plugin.option("clnurl_success_message") {
+        Some(Value::String(message)) => Some(message),
+        Some(Value::OptString) => None,
+        _ => None,
+    };
+
+    let success_url = match plugin.option("clnurl_success_url") {
+        Some(Value::String(url)) => Some(url.parse()?),
+        Some(Value::OptString) => None,
+        _ => None,
+    };
+
+    let nostr_keys = match plugin.option("clnurl_nostr_privkey") {
+        Some(Value::String(privkey)) => {
+            Some(Keys::from_sk_str(&privkey).map_err(|_e| anyhow::anyhow!("Invalid nostr key"))?)
+        }
+        Some(Value::OptString) => None,
+        _ => None,
+    };
+
+    let relays: Vec<String> = match plugin.option("clnurl_relays") {
+        Some(Value::String(relays)) => relays
+            .split(',')
+            .map(|relay| relay.trim().to_string())
+            .filter(|relay| !relay.is_empty())
+            .collect(),
+        _ => vec![],
+    };
+
+    let users: HashMap<String, UserConfig> = match plugin.option("clnurl_users_file") {
+        Some(Value::String(path)) => {
+            let contents = std::fs::read_to_string(path)?;
+            serde_json::from_str(&contents)?
+        }
+        _ => HashMap::new(),
+    };
+
+    let comment_allowed: u64 = match plugin.option("clnurl_comment_allowed") {
+        Some(Value::Integer(len)) => len.try_into().unwrap_or(0),
+        _ => 0,
+    };
+
+    // Reused across invoices so the configured relays are connected once, not per zap.
+    // Relays named in a zap request are added to this client and stay connected.
+    let zap_client: Option<Client> = match &nostr_keys {
+        Some(keys) => {
+            let client = Client::new(keys);
+            for relay in &relays {
+                client.add_relay(relay.as_str(), None).await?;
+            }
+            client.connect().await;
+            Some(client)
+        }
+        None => None,
+    };
+
     let state = ClnurlState {
         rpc_socket,
         api_base_address,
         description,
         nostr_pubkey,
+        success_message,
+        success_url,
+        nostr_keys,
+        relays,
+        users: Arc::new(users),
+        comment_allowed,
+        zap_client,
+        invoices: Arc::new(Mutex::new(HashMap::new())),
     };
 
     let lnurl_service = Router::new()
         .route("/lnurl", get(get_lnurl_struct))
         .route("/invoice", get(get_invoice))
+        .route("/verify/:payment_hash", get(verify_invoice))
+        .route("/.well-known/lnurlp/:username", get(get_lnurl_for_user))
+        .route("/qr/:username", get(get_lnurl_qr))
         .with_state(state);
 
     axum::Server::bind(&listen_addr)
@@ -106,6 +215,24 @@ struct ClnurlState {
     api_base_address: Url,
     description: String,
     nostr_pubkey: Option<String>,
+    success_message: Option<String>,
+    success_url: Option<Url>,
+    nostr_keys: Option<Keys>,
+    relays: Vec<String>,
+    users: Arc<HashMap<String, UserConfig>>,
+    comment_allowed: u64,
+    zap_client: Option<Client>,
+    invoices: Arc<Mutex<HashMap<String, String>>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct UserConfig {
+    description: String,
+    #[serde(default)]
+    min_sendable: Option<u64>,
+    #[serde(default)]
+    max_sendable: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -119,6 +246,8 @@ struct LnurlResponse {
     allows_nostr: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     nostr_pubkey: Option<String>,
+    verify: Url,
+    comment_allowed: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -142,13 +271,81 @@ async fn get_lnurl_struct(
         tag: LnurlTag::PayRequest,
         allows_nostr: state.nostr_pubkey.is_some(),
         nostr_pubkey: state.nostr_pubkey,
+        verify: state
+            .api_base_address
+            .join("verify/")
+            .expect("Still a valid URL"),
+        comment_allowed: state.comment_allowed,
+    }))
+}
+
+async fn get_lnurl_for_user(
+    Path(username): Path<String>,
+    State(state): State<ClnurlState>,
+) -> Result<Json<LnurlResponse>, StatusCode> {
+    let user = state.users.get(&username).ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut callback = state
+        .api_base_address
+        .join("invoice")
+        .expect("Still a valid URL");
+    callback.query_pairs_mut().append_pair("user", &username);
+
+    Ok(Json(LnurlResponse {
+        min_sendable: AmountWrapper::from_msat(user.min_sendable.unwrap_or(1)),
+        max_sendable: AmountWrapper::from_msat(user.max_sendable.unwrap_or(100000000000)),
+        metadata: serde_json::to_string(&vec![vec![
+            "text/plain".to_string(),
+            user.description.clone(),
+        ]])
+        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?,
+        callback,
+        tag: LnurlTag::PayRequest,
+        allows_nostr: state.nostr_pubkey.is_some(),
+        nostr_pubkey: state.nostr_pubkey,
+        verify: state
+            .api_base_address
+            .join("verify/")
+            .expect("Still a valid URL"),
+        comment_allowed: state.comment_allowed,
     }))
 }
 
+async fn get_lnurl_qr(
+    Path(username): Path<String>,
+    State(state): State<ClnurlState>,
+) -> Result<String, StatusCode> {
+    if !state.users.contains_key(&username) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let lnurlp_url = state
+        .api_base_address
+        .join(&format!(".well-known/lnurlp/{username}"))
+        .expect("Still a valid URL");
+
+    encode_lnurl(&lnurlp_url).map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn encode_lnurl(url: &Url) -> Result<String, bech32::Error> {
+    let data = url.as_str().as_bytes().to_base32();
+    let encoded = bech32::encode("lnurl", data, Variant::Bech32)?;
+    Ok(encoded.to_uppercase())
+}
+
 #[derive(Serialize, Deserialize)]
 struct GetInvoiceParams {
     amount: AmountWrapper,
     nostr: Option<String>,
+    user: Option<String>,
+    comment: Option<String>,
+}
+
+fn comment_within_limit(comment: &Option<String>, comment_allowed: u64) -> bool {
+    match comment {
+        Some(comment) => comment_allowed > 0 && comment.len() as u64 <= comment_allowed,
+        None => true,
+    }
 }
 
 #[derive(Debug)]
@@ -190,12 +387,29 @@ impl From<AmountWrapper> for Amount {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "tag", rename_all = "camelCase")]
+enum SuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: Url,
+    },
+    Aes {
+        description: String,
+        ciphertext: String,
+        iv: String,
+    },
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct GetInvoiceResponse {
     pr: String,
-    // TODO: find out proper type
-    success_action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success_action: Option<SuccessAction>,
     // TODO: find out proper type
     routes: Vec<String>,
 }
@@ -208,24 +422,52 @@ async fn get_invoice(
         .await
         .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let description = match &params.nostr {
+    let zap_request: Option<Event> = match &params.nostr {
         Some(d) => {
             let zap_request: Event =
                 Event::from_json(d).map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
             zap_request
                 .verify()
                 .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
-            zap_request.as_json()
+            Some(zap_request)
         }
-        None => serde_json::to_string(&vec![vec!["text/plain".to_string(), state.description]])
-            .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => None,
+    };
+
+    let user = match &params.user {
+        Some(username) => Some(state.users.get(username).ok_or(StatusCode::NOT_FOUND)?),
+        None => None,
     };
 
+    if let Some(user) = user {
+        let msat = params.amount.msat();
+        if msat < user.min_sendable.unwrap_or(1) || msat > user.max_sendable.unwrap_or(u64::MAX) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    if !comment_within_limit(&params.comment, state.comment_allowed) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let description = match &zap_request {
+        Some(zap_request) => zap_request.as_json(),
+        None => {
+            let description = user
+                .map(|user| user.description.clone())
+                .unwrap_or_else(|| state.description.clone());
+            serde_json::to_string(&vec![vec!["text/plain".to_string(), description]])
+                .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+    };
+
+    let label = Uuid::new_v4().to_string();
+
     let cln_response = cln_client
         .call(cln_rpc::Request::Invoice(InvoiceRequest {
             amount_msat: AmountOrAny::Amount(params.amount.into()),
             description,
-            label: Uuid::new_v4().to_string(),
+            label: label.clone(),
             expiry: None,
             fallbacks: None,
             preimage: None,
@@ -236,18 +478,194 @@ async fn get_invoice(
         .await
         .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let invoice = match cln_response {
-        cln_rpc::Response::Invoice(invoice_response) => invoice_response.bolt11,
+    let invoice_response = match cln_response {
+        cln_rpc::Response::Invoice(invoice_response) => invoice_response,
         _ => panic!("CLN returned wrong response kind"),
     };
 
+    let invoice = invoice_response.bolt11;
+
+    if let Some(comment) = &params.comment {
+        // Not hashed into the description (would break LUD-06 verification); just logged.
+        eprintln!("Comment for invoice {}: {comment}", invoice_response.payment_hash);
+    }
+
+    let payment_hash = invoice_response.payment_hash.to_string();
+    state
+        .invoices
+        .lock()
+        .await
+        .insert(payment_hash.clone(), label.clone());
+
+    // Invoices that are never settled or polled via /verify would otherwise sit in
+    // `invoices` forever; sweep this one out once CLN would have expired it.
+    let invoices = state.invoices.clone();
+    let expires_at = invoice_response.expires_at;
+    tokio::spawn(async move {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(expires_at);
+        tokio::time::sleep(std::time::Duration::from_secs(expires_at.saturating_sub(now))).await;
+        invoices.lock().await.remove(&payment_hash);
+    });
+
+    if let (Some(zap_request), Some(keys), Some(client)) =
+        (zap_request, state.nostr_keys, state.zap_client)
+    {
+        let rpc_socket = state.rpc_socket.clone();
+        let invoice = invoice.clone();
+        tokio::spawn(async move {
+            if let Err(err) =
+                publish_zap_receipt(rpc_socket, client, keys, label, invoice, zap_request).await
+            {
+                eprintln!("Failed to publish zap receipt: {err}");
+            }
+        });
+    }
+
+    let success_action = match (&state.success_message, &state.success_url) {
+        (Some(message), _) => Some(SuccessAction::Message {
+            message: message.clone(),
+        }),
+        (None, Some(url)) => Some(SuccessAction::Url {
+            description: state.description.clone(),
+            url: url.clone(),
+        }),
+        (None, None) => None,
+    };
+
     Ok(Json(GetInvoiceResponse {
         pr: invoice,
-        success_action: None,
+        success_action,
         routes: vec![],
     }))
 }
 
+async fn publish_zap_receipt(
+    rpc_socket: PathBuf,
+    client: Client,
+    keys: Keys,
+    label: String,
+    invoice: String,
+    zap_request: Event,
+) -> anyhow::Result<()> {
+    let mut cln_client = cln_rpc::ClnRpc::new(&rpc_socket).await?;
+
+    let cln_response = cln_client
+        .call(cln_rpc::Request::WaitInvoice(WaitinvoiceRequest { label }))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    let wait_response = match cln_response {
+        cln_rpc::Response::WaitInvoice(wait_response) => wait_response,
+        _ => anyhow::bail!("CLN returned wrong response kind"),
+    };
+
+    if !matches!(wait_response.status, WaitinvoiceStatus::PAID) {
+        return Ok(());
+    }
+
+    let mut tags = vec![
+        Tag::Bolt11(invoice),
+        Tag::Description(zap_request.as_json()),
+    ];
+
+    if let Some(preimage) = wait_response.payment_preimage {
+        tags.push(Tag::Preimage(to_hex(&preimage.to_vec())));
+    }
+
+    for tag in &zap_request.tags {
+        if matches!(tag.kind(), TagKind::P | TagKind::E | TagKind::A) {
+            tags.push(tag.clone());
+        }
+
+        if let Tag::Relays(relay_tag) = tag {
+            for relay in relay_tag {
+                let relay = relay.to_string();
+                client.add_relay(relay.as_str(), None).await?;
+                client.connect_relay(relay.as_str()).await?;
+            }
+        }
+    }
+
+    let zap_receipt = EventBuilder::new(Kind::ZapReceipt, "", &tags).to_event(&keys)?;
+
+    client.send_event(zap_receipt).await?;
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyResponse {
+    status: String,
+    settled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preimage: Option<String>,
+    pr: String,
+}
+
+async fn verify_invoice(
+    Path(payment_hash): Path<String>,
+    State(state): State<ClnurlState>,
+) -> Result<Json<VerifyResponse>, StatusCode> {
+    {
+        let invoices = state.invoices.lock().await;
+        if !invoices.contains_key(&payment_hash) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+    }
+
+    let mut cln_client = cln_rpc::ClnRpc::new(&state.rpc_socket)
+        .await
+        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let cln_response = cln_client
+        .call(cln_rpc::Request::ListInvoices(ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: None,
+            limit: None,
+            offer_id: None,
+            payment_hash: Some(payment_hash.clone()),
+            start: None,
+        }))
+        .await
+        .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let invoice = match cln_response {
+        cln_rpc::Response::ListInvoices(response) => response
+            .invoices
+            .into_iter()
+            .next()
+            .ok_or(StatusCode::NOT_FOUND)?,
+        _ => panic!("CLN returned wrong response kind"),
+    };
+
+    let settled = matches!(invoice.status, ListinvoicesInvoicesStatus::PAID);
+
+    if !matches!(invoice.status, ListinvoicesInvoicesStatus::UNPAID) {
+        // Paid or expired: nothing further to verify, stop tracking it.
+        state.invoices.lock().await.remove(&payment_hash);
+    }
+
+    Ok(Json(VerifyResponse {
+        status: "OK".to_string(),
+        settled,
+        preimage: if settled {
+            invoice.payment_preimage.map(|secret| to_hex(&secret.to_vec()))
+        } else {
+            None
+        },
+        pr: invoice.bolt11.unwrap_or_default(),
+    }))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -271,8 +689,35 @@ mod tests {
             nostr_pubkey: Some(
                 "9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31".to_string(),
             ),
+            verify: Url::from_str("http://example.com/verify/").unwrap(),
+            comment_allowed: 140,
         };
 
-        assert_eq!("{\"minSendable\":0,\"maxSendable\":1000000,\"metadata\":\"[[\\\"text/plain\\\",\\\"Hello world\\\"]]\",\"callback\":\"http://example.com/\",\"tag\":\"payRequest\",\"allowsNostr\":true,\"nostrPubkey\":\"9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31\"}", serde_json::to_string(&lnurl_response).unwrap());
+        assert_eq!("{\"minSendable\":0,\"maxSendable\":1000000,\"metadata\":\"[[\\\"text/plain\\\",\\\"Hello world\\\"]]\",\"callback\":\"http://example.com/\",\"tag\":\"payRequest\",\"allowsNostr\":true,\"nostrPubkey\":\"9630f464cca6a5147aa8a35f0bcdd3ce485324e732fd39e09233b1d848238f31\",\"verify\":\"http://example.com/verify/\",\"commentAllowed\":140}", serde_json::to_string(&lnurl_response).unwrap());
+    }
+
+    #[test]
+    fn test_encode_lnurl_round_trips() {
+        use bech32::FromBase32;
+
+        let url = Url::from_str("https://example.com/.well-known/lnurlp/satoshi").unwrap();
+        let encoded = encode_lnurl(&url).unwrap();
+
+        assert!(encoded.starts_with("LNURL1"));
+
+        let (hrp, data, variant) = bech32::decode(&encoded.to_lowercase()).unwrap();
+        assert_eq!(hrp, "lnurl");
+        assert_eq!(variant, Variant::Bech32);
+        let decoded = Vec::<u8>::from_base32(&data).unwrap();
+        assert_eq!(String::from_utf8(decoded).unwrap(), url.as_str());
+    }
+
+    #[test]
+    fn test_comment_within_limit() {
+        assert!(comment_within_limit(&None, 0));
+        assert!(comment_within_limit(&None, 140));
+        assert!(!comment_within_limit(&Some("hi".to_string()), 0));
+        assert!(comment_within_limit(&Some("hi".to_string()), 2));
+        assert!(!comment_within_limit(&Some("hello".to_string()), 2));
     }
 }